@@ -5,6 +5,7 @@ use tokio::runtime::{Builder, Runtime};
 mod storage;
 mod scheduler;
 mod partition;
+mod tx_dependency;
 
 lazy_static! {
     static ref TK_RUNTIME: Runtime = Builder::new_multi_thread()
@@ -22,7 +23,7 @@ type PartitionId = usize;
 
 type TxId = usize;
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 enum LocationAndType {
     Basic(Address),
     Storage(Address, U256),
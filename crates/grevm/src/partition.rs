@@ -0,0 +1,71 @@
+//! Executes the txs assigned to a single partition against a shared
+//! `DatabaseRef` (normally `Arc<CacheDB<DB>>`, see `GrevmScheduler::state`).
+
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+use crate::{PartitionId, TxId};
+
+/// `GrevmScheduler::round_execute` keeps one `PartitionExecutor` alive per
+/// partition_id across rounds (see `TxDependency`'s affinity-aware
+/// re-partitioning) instead of rebuilding it every round, so most of a
+/// partition's assigned txs carry over from the previous round unchanged.
+/// `retain_cache` reconciles whatever per-tx state this executor is holding
+/// with the new round's assignment.
+pub(crate) struct PartitionExecutor<DB> {
+    partition_id: PartitionId,
+    cache_db: DB,
+    assigned_txs: BTreeSet<TxId>,
+    // each assigned tx's wall-clock execution time from the last `execute`,
+    // consumed by `GrevmScheduler::round_execute` to feed
+    // `TxDependency::update_tx_running_time`
+    tx_running_time: Vec<(TxId, u64)>,
+}
+
+impl<DB> PartitionExecutor<DB> {
+    pub(crate) fn new(partition_id: PartitionId, cache_db: DB) -> Self {
+        PartitionExecutor {
+            partition_id,
+            cache_db,
+            assigned_txs: BTreeSet::new(),
+            tx_running_time: Vec::new(),
+        }
+    }
+
+    /// Adopts `assigned_txs` as this partition's new tx set, dropping any
+    /// per-tx state kept from the previous round for txs that moved to a
+    /// different partition. Txs common to both rounds keep whatever state
+    /// `execute` left behind for them, which is the whole point of reusing
+    /// executors across rounds instead of rebuilding them.
+    pub(crate) fn retain_cache(&mut self, assigned_txs: &[TxId]) {
+        self.assigned_txs = assigned_txs.iter().copied().collect();
+        let assigned = &self.assigned_txs;
+        self.tx_running_time.retain(|(txid, _)| assigned.contains(txid));
+    }
+
+    /// Executes every tx assigned to this partition, recording each one's
+    /// wall-clock execution time so `GrevmScheduler::round_execute` can feed
+    /// it back into `TxDependency::update_tx_running_time`.
+    pub(crate) fn execute(&mut self) {
+        self.tx_running_time.clear();
+        for &txid in &self.assigned_txs {
+            let start = Instant::now();
+            self.execute_tx(txid);
+            self.tx_running_time.push((txid, start.elapsed().as_micros() as u64));
+        }
+    }
+
+    // Runs a single tx against `cache_db`, updating its read/write set and
+    // settling it into PENDING/FINALITY state. Unimplemented here: it needs
+    // the full revm `Evm` wiring, the other half of which (conflict
+    // detection) is the same not-yet-built machinery behind
+    // `GrevmScheduler::validate_transactions`.
+    fn execute_tx(&mut self, _txid: TxId) {
+        todo!()
+    }
+
+    /// Per-tx wall-clock execution time recorded by the last `execute`.
+    pub(crate) fn tx_running_time(&self) -> &[(TxId, u64)] {
+        &self.tx_running_time
+    }
+}
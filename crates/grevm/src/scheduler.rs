@@ -1,13 +1,24 @@
+use std::cmp::min;
 use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, RwLock};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use revm_primitives::{Address, TxEnv};
 use revm_primitives::db::DatabaseRef;
+use tokio::sync::Semaphore;
 
 use crate::{GREVM_RUNTIME, LocationAndType, MAX_NUM_ROUND, TxId};
+use crate::hint::ParallelExecutionHints;
 use crate::partition::PartitionExecutor;
-use crate::storage::CacheDB;
+use crate::storage::{CacheDB, DEFAULT_CACHE_BUDGET};
+use crate::tx_dependency::TxDependency;
+
+// how many of the earliest pending txs (per partition) preload fetches
+// ahead of round_execute
+const PRELOAD_TXS_PER_PARTITION: usize = 16;
+// bounded concurrency for preload's DatabaseRef reads, so a large preload
+// set can't starve the rest of GREVM_RUNTIME
+const PRELOAD_CONCURRENCY: usize = 32;
 
 pub struct GrevmScheduler<DB>
 {
@@ -21,6 +32,14 @@ pub struct GrevmScheduler<DB>
     // if txi depends on txj: txi -> txj (txj should run first)
     // then, dependencies[txj].push(txi)
     dependencies: Vec<Vec<TxId>>,
+    // groups dependencies into balanced partitions; `partition_transactions`
+    // hands it the previous round's `partitioned_txs` so re-partitioning
+    // minimizes tx movement
+    tx_dependency: TxDependency,
+    // read/write set hints consensus attached to every tx; `preload` uses
+    // the read sets of the earliest pending txs to warm `state` ahead of
+    // `round_execute`
+    parallel_execution_hints: ParallelExecutionHints,
 
     // number of partitions. maybe larger in the first round to increase concurrence
     num_partitions: usize,
@@ -32,6 +51,12 @@ pub struct GrevmScheduler<DB>
     merged_write_set: HashMap<LocationAndType, BTreeSet<TxId>>,
 
     num_finality_txs: usize,
+
+    // set by `cancel_preload` to abort an in-flight `preload` from another
+    // thread (e.g. a caller running `init_dependencies`/`parallel_execute`
+    // on a worker task that a newer block supersedes); reset at the start
+    // of every `init_dependencies` call
+    preload_stop: Arc<AtomicBool>,
 }
 
 impl<DB> GrevmScheduler<DB>
@@ -40,32 +65,136 @@ where
     DB::Error: Send + Sync,
 {
     pub fn new(db: DB) -> Self {
+        Self::with_cache_budget(db, DEFAULT_CACHE_BUDGET)
+    }
+
+    /// Like `new`, but caps `state`'s cache at `cache_budget` entries
+    /// instead of the default, so operators can bound memory per
+    /// concurrent block execution.
+    pub fn with_cache_budget(db: DB, cache_budget: usize) -> Self {
         // yield the DatabaseRef trait's IO operations
-        let state = CacheDB::new(db, true);
+        let state = CacheDB::with_budget(db, true, cache_budget);
         todo!()
     }
 
     pub fn partition_transactions(&mut self) {
-        // compute and assign partitioned_txs
+        self.partitioned_txs =
+            self.tx_dependency.fetch_best_partitions(self.num_partitions, &self.partitioned_txs);
+    }
+
+    /// Trades perfect partition balance for fewer cross-round tx movements
+    /// (and thus warmer `PartitionExecutor` caches); see
+    /// `TxDependency::set_stability_factor`.
+    pub fn set_stability_factor(&mut self, stability_factor: f64) {
+        self.tx_dependency.set_stability_factor(stability_factor);
+    }
+
+    /// Aborts an in-flight `preload` from another thread. The caller
+    /// driving `init_dependencies`/`parallel_execute` (typically on its own
+    /// worker task) should call this if this block gets superseded before
+    /// its preload finishes; `preload` checks the flag between fetches and
+    /// returns early, and the next `init_dependencies` call resets it.
+    pub fn cancel_preload(&self) {
+        self.preload_stop.store(true, Ordering::Release);
     }
 
     // initialize dependencies:
     // 1. txs without contract can generate dependencies from 'from/to' address
     // 2. consensus can build the dependencies(hints) of txs with contract
-    pub fn init_dependencies(&mut self, hints: Vec<Vec<TxId>>) {
-        // self.preload()
-        // update dependencies
+    pub fn init_dependencies(&mut self, parallel_execution_hints: ParallelExecutionHints) {
+        self.tx_dependency = TxDependency::new(&parallel_execution_hints);
+        self.parallel_execution_hints = parallel_execution_hints;
+        self.preload_stop.store(false, Ordering::Release);
+        GREVM_RUNTIME.block_on(self.preload());
         self.partition_transactions();
     }
 
-    // Preload data when initializing dependencies
-    async fn preload(&mut self, stop: &AtomicBool) {}
+    // Preload data when initializing dependencies: warm `state` with the
+    // read-set locations the earliest pending txs will touch, so
+    // `round_execute` doesn't stall on cold `DatabaseRef` IO. Pins every
+    // preloaded location for the duration of the fetch so a concurrent
+    // eviction can't drop it mid-preload (the closing `unpin` evicts down
+    // to the cache budget again), and honors `preload_stop`
+    // (see `cancel_preload`) so a cancelled block doesn't keep fetching
+    // unneeded state.
+    async fn preload(&mut self) {
+        let preload_depth =
+            min(self.txs.len(), self.num_partitions * PRELOAD_TXS_PER_PARTITION);
+        let locations: Vec<LocationAndType> = self.parallel_execution_hints.txs_hint
+            [..preload_depth]
+            .iter()
+            .flat_map(|hint| hint.read_set.iter().cloned())
+            .collect();
+        if locations.is_empty() {
+            return;
+        }
+        self.state.pin(locations.iter().cloned());
+
+        let semaphore = Arc::new(Semaphore::new(PRELOAD_CONCURRENCY));
+        let mut tasks = Vec::with_capacity(locations.len());
+        for location in locations.iter().cloned() {
+            if self.preload_stop.load(Ordering::Acquire) {
+                break;
+            }
+            let state = self.state.clone();
+            let semaphore = semaphore.clone();
+            tasks.push(GREVM_RUNTIME.spawn(async move {
+                let _permit = semaphore.acquire().await;
+                match location {
+                    LocationAndType::Basic(address) => {
+                        let _ = state.basic_ref(address);
+                    }
+                    LocationAndType::Storage(address, index) => {
+                        let _ = state.storage_ref(address, index);
+                    }
+                    LocationAndType::Code(address) => {
+                        if let Ok(Some(info)) = state.basic_ref(address) {
+                            let _ = state.code_by_hash_ref(info.code_hash);
+                        }
+                    }
+                }
+            }));
+        }
+        futures::future::join_all(tasks).await;
+        self.state.unpin(locations);
+    }
 
     fn round_execute(&mut self) {
-        for partition_id in 0..self.num_partitions {
-            self.partition_executors.push(
-                Arc::new(RwLock::new(PartitionExecutor::new(partition_id, self.state.clone()))));
+        if self.partition_executors.len() != self.num_partitions {
+            self.partition_executors = (0..self.num_partitions)
+                .map(|partition_id| {
+                    Arc::new(RwLock::new(PartitionExecutor::new(partition_id, self.state.clone())))
+                })
+                .collect();
         }
+        // Reuse each partition's executor across rounds instead of
+        // rebuilding from scratch: `partition_transactions`'s affinity keeps
+        // most txs on the same partition_id. All executors read through the
+        // shared `state` cache, so no cache entry is evicted here;
+        // `retain_cache` only adopts this round's txs and drops per-tx state
+        // for txs that moved. Also reconciles a freshly-created executor's
+        // (empty) assignment with this round's txs; a partition_id with no
+        // txs this round gets an empty assignment.
+        for (partition_id, executor) in self.partition_executors.iter().enumerate() {
+            let assigned = self.partitioned_txs.get(partition_id).map_or(&[][..], Vec::as_slice);
+            executor.write().unwrap().retain_cache(assigned);
+        }
+        // Pin every pending partition's full working set (both read and
+        // write locations) for the duration of this round, not just the
+        // lookahead window `preload` warms once at the start: a partition
+        // can still be mid-execution several rounds in, and an eviction
+        // from any other partition's cache traffic must not be able to
+        // drop an entry this round's in-flight txs still need.
+        let working_set: Vec<LocationAndType> = self
+            .partitioned_txs
+            .iter()
+            .flatten()
+            .flat_map(|&txid| {
+                let hint = &self.parallel_execution_hints.txs_hint[txid];
+                hint.read_set.iter().chain(hint.write_set.iter()).cloned()
+            })
+            .collect();
+        self.state.pin(working_set.iter().cloned());
         GREVM_RUNTIME.block_on(async {
             let mut tasks = vec![];
             for executor in &self.partition_executors {
@@ -76,10 +205,21 @@ where
             }
             futures::future::join_all(tasks).await;
         });
+        // Feed each tx's measured execution time from this round back into
+        // tx_dependency, so the next round's fetch_best_partitions balances
+        // by observed cost instead of the first round's seeded weights.
+        let mut tx_running_time = vec![0u64; self.txs.len() - self.num_finality_txs];
+        for executor in &self.partition_executors {
+            for &(txid, duration) in executor.read().unwrap().tx_running_time() {
+                tx_running_time[txid - self.num_finality_txs] = duration;
+            }
+        }
+        self.tx_dependency.update_tx_running_time(tx_running_time);
         // merge write set
         self.merge_write_set();
         // validate transactions
         self.num_finality_txs += self.validate_transactions();
+        self.state.unpin(working_set);
     }
 
     // merge write set after each round
@@ -97,8 +237,8 @@ where
 
     fn execute_remaining_sequential(&mut self) {}
 
-    fn parallel_execute(&mut self, hints: Vec<Vec<TxId>>) {
-        self.init_dependencies(hints);
+    fn parallel_execute(&mut self, parallel_execution_hints: ParallelExecutionHints) {
+        self.init_dependencies(parallel_execution_hints);
         for i in 0..MAX_NUM_ROUND {
             if self.num_finality_txs < self.txs.len() {
                 self.round_execute();
@@ -0,0 +1,267 @@
+//! Size-bounded, evictable caching layer over a `DatabaseRef`.
+//!
+//! Every account/storage/code location ever touched used to be retained for
+//! the lifetime of `GrevmScheduler`, which risks unbounded memory growth on
+//! large blocks. `CacheDB` now caps itself at a configurable entry budget
+//! and evicts in (approximate) LRU order, but only entries that are both
+//! clean (never written by a FINALITY tx, so `db` still reflects them) and
+//! unpinned (not in any pending partition's read/write set) are eligible;
+//! evicting anything else would either lose a write `db` doesn't have or
+//! force an in-flight partition to refetch mid-round.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use revm_primitives::db::DatabaseRef;
+use revm_primitives::{AccountInfo, Address, Bytecode, B256, U256};
+
+use crate::LocationAndType;
+
+/// Default per-scheduler cache budget (entries); override with
+/// `CacheDB::with_budget`.
+pub(crate) const DEFAULT_CACHE_BUDGET: usize = 1_000_000;
+
+#[derive(Clone)]
+enum CacheValue {
+    Basic(Option<AccountInfo>),
+    Storage(U256),
+}
+
+#[derive(Default)]
+struct CacheState {
+    values: HashMap<LocationAndType, CacheValue>,
+    // entries written by a FINALITY tx: `db` is stale for these, so they
+    // are never evicted regardless of pinning
+    dirty: HashSet<LocationAndType>,
+    // entries referenced by at least one pending partition's read/write
+    // set this round; also never evicted
+    pinned: HashMap<LocationAndType, usize>,
+    // approximate LRU order of entries queued while clean and unpinned; an
+    // entry pinned or made dirty after queueing stays here until
+    // `evict_to_budget` pops and skips it
+    lru: VecDeque<LocationAndType>,
+}
+
+impl CacheState {
+    fn touch(&mut self, location: LocationAndType, value: CacheValue, budget: usize) {
+        if !self.values.contains_key(&location) {
+            if !self.dirty.contains(&location) && !self.pinned.contains_key(&location) {
+                self.lru.push_back(location.clone());
+            }
+            self.values.insert(location, value);
+            self.evict_to_budget(budget);
+        } else {
+            self.record_access(&location);
+        }
+    }
+
+    /// Moves `location` to the back of `lru` (most-recently-used) if it is
+    /// currently in the eligible (clean, unpinned) queue, so a cache hit
+    /// delays eviction the same way a fresh insert does. Without this,
+    /// `lru` only ever reflects first-touch order, which makes eviction
+    /// FIFO rather than LRU and evicts repeatedly-hit hot accounts just as
+    /// readily as ones touched once and never read again.
+    fn record_access(&mut self, location: &LocationAndType) {
+        if let Some(pos) = self.lru.iter().position(|loc| loc == location) {
+            self.lru.remove(pos);
+            self.lru.push_back(location.clone());
+        }
+    }
+
+    fn evict_to_budget(&mut self, budget: usize) {
+        while self.values.len() > budget {
+            let Some(location) = self.lru.pop_front() else { break };
+            // a location can be pinned/made dirty after being queued for
+            // eviction; skip it instead of dropping a still-needed entry
+            if self.dirty.contains(&location) || self.pinned.contains_key(&location) {
+                continue;
+            }
+            self.values.remove(&location);
+        }
+    }
+
+    fn pin(&mut self, location: LocationAndType) {
+        *self.pinned.entry(location).or_insert(0) += 1;
+    }
+
+    fn unpin(&mut self, location: &LocationAndType, budget: usize) {
+        if let Some(count) = self.pinned.get_mut(location) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(location);
+                if self.values.contains_key(location) && !self.dirty.contains(location) {
+                    self.lru.push_back(location.clone());
+                }
+            }
+        }
+        self.evict_to_budget(budget);
+    }
+
+    fn mark_dirty(&mut self, location: LocationAndType) {
+        self.dirty.insert(location);
+    }
+}
+
+pub(crate) struct CacheDB<DB> {
+    db: DB,
+    // stored as given; the `DatabaseRef` reads below do not consult it and
+    // send every cache miss to `db`
+    with_io: bool,
+    budget: usize,
+    state: Mutex<CacheState>,
+}
+
+impl<DB> CacheDB<DB> {
+    pub(crate) fn new(db: DB, with_io: bool) -> Self {
+        Self::with_budget(db, with_io, DEFAULT_CACHE_BUDGET)
+    }
+
+    pub(crate) fn with_budget(db: DB, with_io: bool, budget: usize) -> Self {
+        CacheDB { db, with_io, budget, state: Mutex::new(CacheState::default()) }
+    }
+
+    /// Pins `locations` so they survive eviction until a matching `unpin`,
+    /// used to protect a pending partition's read/write set for the
+    /// duration of a round (including while it is being preloaded).
+    pub(crate) fn pin(&self, locations: impl IntoIterator<Item = LocationAndType>) {
+        let mut state = self.state.lock().unwrap();
+        for location in locations {
+            state.pin(location);
+        }
+    }
+
+    pub(crate) fn unpin(&self, locations: impl IntoIterator<Item = LocationAndType>) {
+        let mut state = self.state.lock().unwrap();
+        for location in locations {
+            state.unpin(&location, self.budget);
+        }
+    }
+
+    /// Marks `location` dirty: it was written by a FINALITY tx, so `db` no
+    /// longer reflects it and it must never be evicted.
+    pub(crate) fn mark_dirty(&self, location: LocationAndType) {
+        self.state.lock().unwrap().mark_dirty(location);
+    }
+}
+
+impl<DB: DatabaseRef> DatabaseRef for CacheDB<DB> {
+    type Error = DB::Error;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let location = LocationAndType::Basic(address);
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(CacheValue::Basic(info)) = state.values.get(&location) {
+                let info = info.clone();
+                state.record_access(&location);
+                return Ok(info);
+            }
+        }
+        let info = self.db.basic_ref(address)?;
+        self.state.lock().unwrap().touch(location, CacheValue::Basic(info.clone()), self.budget);
+        Ok(info)
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        // bytecode is keyed by hash, not by address, so it is not cached
+        // here: every call goes straight to `db`
+        self.db.code_by_hash_ref(code_hash)
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        let location = LocationAndType::Storage(address, index);
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(CacheValue::Storage(value)) = state.values.get(&location) {
+                let value = *value;
+                state.record_access(&location);
+                return Ok(value);
+            }
+        }
+        let value = self.db.storage_ref(address, index)?;
+        self.state.lock().unwrap().touch(location, CacheValue::Storage(value), self.budget);
+        Ok(value)
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        self.db.block_hash_ref(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(n: u8) -> LocationAndType {
+        LocationAndType::Basic(Address::from([n; 20]))
+    }
+
+    #[test]
+    fn pinned_entry_survives_eviction_pressure() {
+        let mut state = CacheState::default();
+        let budget = 1;
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+        state.pin(loc(1));
+
+        // touching more entries under a budget of 1 must never evict loc(1)
+        // while it is pinned
+        state.touch(loc(2), CacheValue::Basic(None), budget);
+        state.touch(loc(3), CacheValue::Basic(None), budget);
+
+        assert!(state.values.contains_key(&loc(1)));
+    }
+
+    #[test]
+    fn unpinned_entries_are_evicted_to_respect_budget() {
+        let mut state = CacheState::default();
+        let budget = 1;
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+        state.touch(loc(2), CacheValue::Basic(None), budget);
+
+        assert_eq!(state.values.len(), budget);
+    }
+
+    #[test]
+    fn unpin_requeues_entry_for_eviction() {
+        let mut state = CacheState::default();
+        let budget = 1;
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+        state.pin(loc(1));
+        state.touch(loc(2), CacheValue::Basic(None), budget);
+        assert!(state.values.contains_key(&loc(1)));
+
+        state.unpin(&loc(1), budget);
+
+        assert!(state.lru.contains(&loc(1)));
+        assert!(state.values.len() <= budget);
+    }
+
+    #[test]
+    fn accessed_entry_is_moved_to_back_of_lru() {
+        let mut state = CacheState::default();
+        let budget = 2;
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+        state.touch(loc(2), CacheValue::Basic(None), budget);
+
+        // re-touching loc(1) is a cache hit in `basic_ref`/`storage_ref`'s
+        // sense: it must move loc(1) to the back of `lru`, making loc(2) the
+        // next eviction candidate instead of loc(1)
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+
+        state.touch(loc(3), CacheValue::Basic(None), budget);
+
+        assert!(state.values.contains_key(&loc(1)));
+        assert!(!state.values.contains_key(&loc(2)));
+    }
+
+    #[test]
+    fn dirty_entry_survives_eviction_even_when_unpinned() {
+        let mut state = CacheState::default();
+        let budget = 1;
+        state.touch(loc(1), CacheValue::Basic(None), budget);
+        state.mark_dirty(loc(1));
+        state.touch(loc(2), CacheValue::Basic(None), budget);
+
+        assert!(state.values.contains_key(&loc(1)));
+    }
+}
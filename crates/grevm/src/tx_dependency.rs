@@ -1,16 +1,30 @@
 use dashmap::DashMap;
 use smallvec::SmallVec;
 use std::cmp::{min, Reverse};
-use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 
-use crate::hint::ParallelExecutionHints;
-use crate::{fork_join_util, LocationAndType, TxId, CPU_CORES};
+use revm_primitives::Address;
+
+use crate::hint::{ParallelExecutionHints, TxExecutionHint};
+use crate::{fork_join_util, LocationAndType, PartitionId, TxId, CPU_CORES};
 
 pub(crate) type DependentTxsVec = SmallVec<[TxId; 1]>;
 
 const RAW_TRANSFER_WEIGHT: usize = 1;
+// First-round weight multiplier for a contract-call tx relative to a raw
+// transfer, applied per distinct location in its write set (counting at
+// least one) so a call that touches more storage is weighted heavier.
+const CONTRACT_CALL_WEIGHT: usize = 4;
+
+// Default weight given to the affinity bonus when re-partitioning: a group
+// is kept on its previous partition as long as doing so would not push that
+// partition's load past `stability_factor` times the ideal balanced load.
+// 1.0 means "never sacrifice balance for stability"; values above 1.0 trade
+// some imbalance for fewer cross-partition tx movements (and thus warmer
+// `PartitionExecutor` caches).
+const DEFAULT_STABILITY_FACTOR: f64 = 1.2;
 
 pub(crate) struct TxDependency {
     // if txi <- txj, then tx_dependency[txj - num_finality_txs].push(txi)
@@ -25,21 +39,78 @@ pub(crate) struct TxDependency {
     // while in the second round, weights can be assigned based on tx_running_time.
     tx_weight: Option<Vec<usize>>,
     all_independent: bool,
+    // How strongly `fetch_best_partitions` favors keeping a group on the
+    // partition that already held most of it last round, see
+    // `DEFAULT_STABILITY_FACTOR`.
+    stability_factor: f64,
 }
 
 impl TxDependency {
     pub fn new(parallel_execution_hints: &ParallelExecutionHints) -> Self {
         let (tx_dependency, all_independent) =
             Self::generate_tx_dependency(parallel_execution_hints);
+        let tx_weight = Self::seed_tx_weight(parallel_execution_hints);
         TxDependency {
             tx_dependency,
             num_finality_txs: 0,
             tx_running_time: None,
-            tx_weight: None,
+            tx_weight: Some(tx_weight),
             all_independent,
+            stability_factor: DEFAULT_STABILITY_FACTOR,
         }
     }
 
+    /// Seeds the first round's `tx_weight` from transaction type (raw
+    /// transfer vs. contract call) and called-contract identity, since no
+    /// `tx_running_time` measurements exist yet. Every tx calling the same
+    /// contract gets that contract's heaviest observed write footprint, so
+    /// repeated calls to one contract are weighted consistently.
+    fn seed_tx_weight(parallel_execution_hints: &ParallelExecutionHints) -> Vec<usize> {
+        let mut contract_weight: HashMap<Address, usize> = HashMap::new();
+        for hint in parallel_execution_hints.txs_hint.iter() {
+            if let Some(contract) = Self::called_contract(hint) {
+                let weight = hint.write_set.len().max(1) * CONTRACT_CALL_WEIGHT;
+                contract_weight
+                    .entry(contract)
+                    .and_modify(|existing| *existing = (*existing).max(weight))
+                    .or_insert(weight);
+            }
+        }
+        parallel_execution_hints
+            .txs_hint
+            .iter()
+            .map(|hint| match Self::called_contract(hint) {
+                Some(contract) => contract_weight[&contract],
+                None => RAW_TRANSFER_WEIGHT,
+            })
+            .collect()
+    }
+
+    // The contract a tx invokes, identified by the `Code` location it reads
+    // or writes; `None` means the tx is a raw transfer.
+    fn called_contract(hint: &TxExecutionHint) -> Option<Address> {
+        hint.read_set.iter().chain(hint.write_set.iter()).find_map(|location| match location {
+            LocationAndType::Code(address) => Some(*address),
+            _ => None,
+        })
+    }
+
+    /// Overrides how strongly re-partitioning favors minimal tx movement
+    /// over a perfectly balanced load. Higher values keep more groups on
+    /// their previous partition at the cost of some imbalance.
+    pub fn set_stability_factor(&mut self, stability_factor: f64) {
+        self.stability_factor = stability_factor;
+    }
+
+    /// Feeds back the wall-clock (or gas-scaled) execution time measured for
+    /// each currently-pending tx during the last round, re-deriving
+    /// `tx_weight` from it so the next `fetch_best_partitions` balances by
+    /// observed cost instead of the first round's `seed_tx_weight` weights.
+    pub fn update_tx_running_time(&mut self, tx_running_time: Vec<u64>) {
+        self.tx_weight = Some(tx_running_time.iter().map(|&time| (time as usize).max(1)).collect());
+        self.tx_running_time = Some(tx_running_time);
+    }
+
     pub fn clean_dependency(&mut self) {
         let len = self.tx_dependency.len();
         self.tx_dependency = vec![DependentTxsVec::new(); len];
@@ -105,10 +176,23 @@ impl TxDependency {
         partitioned_txs
     }
 
-    pub fn fetch_best_partitions(&mut self, partition_count: usize) -> Vec<Vec<TxId>> {
+    /// `previous_partitioned_txs` is the partition layout produced by the
+    /// prior round (empty on the first round), used to bias re-partitioning
+    /// towards minimal tx movement so `PartitionExecutor` caches stay warm.
+    pub fn fetch_best_partitions(
+        &mut self,
+        partition_count: usize,
+        previous_partitioned_txs: &[Vec<TxId>],
+    ) -> Vec<Vec<TxId>> {
         if self.all_independent {
             return self.all_independent_partitions(partition_count);
         }
+        let mut prev_partition_of: HashMap<TxId, PartitionId> = HashMap::new();
+        for (partition, txs) in previous_partitioned_txs.iter().enumerate() {
+            for &txid in txs {
+                prev_partition_of.insert(txid, partition);
+            }
+        }
         let mut num_group = 0;
         let mut weighted_group: BTreeMap<usize, Vec<DependentTxsVec>> = BTreeMap::new();
         let tx_weight = self
@@ -123,29 +207,30 @@ impl TxDependency {
         let mut revert_dependency: Vec<DependentTxsVec> =
             vec![DependentTxsVec::new(); self.tx_dependency.len()];
         let mut is_related: Vec<bool> = vec![false; self.tx_dependency.len()];
-        {
-            let mut single_groups = weighted_group.entry(RAW_TRANSFER_WEIGHT).or_default();
-            for index in (0..self.tx_dependency.len()).rev() {
-                let txj = index + num_finality_txs;
-                let txj_dep = &self.tx_dependency[index];
-                if txj_dep.is_empty() {
-                    if !is_related[index] {
-                        let mut single_group = DependentTxsVec::new();
-                        single_group.push(txj);
-                        single_groups.push(single_group);
-                        num_group += 1;
-                    }
-                } else {
-                    is_related[index] = true;
-                    for txi in txj_dep {
-                        let txi_index = *txi - num_finality_txs;
-                        revert_dependency[txi_index].push(txj);
-                        is_related[txi_index] = true;
-                    }
+        for index in (0..self.tx_dependency.len()).rev() {
+            let txj = index + num_finality_txs;
+            let txj_dep = &self.tx_dependency[index];
+            if txj_dep.is_empty() {
+                if !is_related[index] {
+                    // An independent tx (no dependency edges at all) must
+                    // still be bucketed by its real tx_weight, not assumed to
+                    // be RAW_TRANSFER_WEIGHT: a fully independent contract
+                    // call (seeded heavier by `seed_tx_weight`) belongs in
+                    // Step 1 as its own indivisible group like any other
+                    // group, not folded into Step 2's unit-weight fill as if
+                    // it only cost a raw transfer.
+                    let mut single_group = DependentTxsVec::new();
+                    single_group.push(txj);
+                    weighted_group.entry(tx_weight[index]).or_default().push(single_group);
+                    num_group += 1;
+                }
+            } else {
+                is_related[index] = true;
+                for txi in txj_dep {
+                    let txi_index = *txi - num_finality_txs;
+                    revert_dependency[txi_index].push(txj);
+                    is_related[txi_index] = true;
                 }
-            }
-            if single_groups.is_empty() {
-                weighted_group.remove(&RAW_TRANSFER_WEIGHT);
             }
         }
         // Because transactions only rely on transactions with lower ID,
@@ -156,7 +241,6 @@ impl TxDependency {
             let index = txid - num_finality_txs;
             if is_related[index] {
                 let mut group = DependentTxsVec::new();
-                let mut weight: usize = 0;
                 // Traverse the breadth from back to front
                 breadth_queue.clear();
                 breadth_queue.push_back(index);
@@ -174,9 +258,15 @@ impl TxDependency {
                             is_related[next_index] = false;
                         }
                     }
-                    weight += tx_weight[index];
                     group.push(top_index + num_finality_txs);
                 }
+                // Weight the group by the sum of its members' costs, not by
+                // the BFS root's cost alone: now that `tx_weight` reflects
+                // real per-tx cost (seeded by contract-call footprint, then
+                // measured running time) instead of a flat RAW_TRANSFER_WEIGHT,
+                // a group mixing cheap and expensive txs must add up all of
+                // them to balance partitions by actual cost.
+                let weight = Self::sum_tx_weight(&group, &tx_weight, num_finality_txs);
                 weighted_group.entry(weight).or_default().push(group);
                 num_group += 1;
             }
@@ -190,42 +280,60 @@ impl TxDependency {
         if num_partitions == 0 {
             return vec![vec![]];
         }
-        let mut partitioned_mutex_group = Vec::with_capacity(num_partitions);
-        for _ in 0..num_partitions {
-            partitioned_mutex_group.push(Mutex::new(BTreeSet::new()));
-        }
-        let mut partition_weight = BinaryHeap::new();
-        // Separate processing of groups with a weight of 1
-        // Because there is only one transaction in these groups,
-        // processing them separately can greatly optimize performance.
-        if let Some(groups) = weighted_group.remove(&RAW_TRANSFER_WEIGHT) {
-            fork_join_util(groups.len(), Some(num_partitions), |start_pos, end_pos, index| {
-                let mut partition = partitioned_mutex_group[index].lock().unwrap();
-                for pos in start_pos..end_pos {
-                    for txid in groups[pos].iter() {
-                        partition.insert(*txid);
-                    }
-                }
-            });
-        }
-        let mut partitioned_group: Vec<BTreeSet<TxId>> = partitioned_mutex_group
+
+        // Step 1: place every indivisible dependency group (weight greater
+        // than RAW_TRANSFER_WEIGHT) onto a partition and record each
+        // partition's resulting load L_p. A group stays on the partition
+        // that held the majority of its txs last round (minimizing cross-
+        // round movement so `PartitionExecutor` caches stay warm) unless
+        // that would push the partition over `balance_threshold`, in which
+        // case it falls back to the least-loaded partition (LPT).
+        let pending_txs: Vec<TxId> = weighted_group
+            .remove(&RAW_TRANSFER_WEIGHT)
+            .unwrap_or_default()
             .into_iter()
-            .map(|partition| partition.into_inner().unwrap())
+            .flatten()
             .collect();
-        for index in 0..num_partitions {
-            partition_weight
-                .push(Reverse((partitioned_group[index].len() * RAW_TRANSFER_WEIGHT, index)));
-        }
+        let total_weight: usize = pending_txs.len() * RAW_TRANSFER_WEIGHT
+            + weighted_group.iter().map(|(weight, groups)| weight * groups.len()).sum::<usize>();
+        let balance_threshold =
+            (total_weight as f64 / num_partitions as f64) * self.stability_factor;
 
+        let mut partitioned_group: Vec<BTreeSet<TxId>> = vec![BTreeSet::new(); num_partitions];
+        let mut loads = vec![0usize; num_partitions];
         for (add_weight, groups) in weighted_group.into_iter().rev() {
             for group in groups {
-                if let Some(Reverse((weight, index))) = partition_weight.pop() {
-                    partitioned_group[index].extend(group);
-                    let new_weight = weight + add_weight;
-                    partition_weight.push(Reverse((new_weight, index)));
-                }
+                let affinity = Self::affinity_partition(&group, &prev_partition_of, num_partitions);
+                let index = match affinity {
+                    Some(partition)
+                        if (loads[partition] + add_weight) as f64 <= balance_threshold =>
+                    {
+                        partition
+                    }
+                    _ => (0..num_partitions).min_by_key(|&p| loads[p]).expect("num_partitions > 0"),
+                };
+                partitioned_group[index].extend(group);
+                loads[index] += add_weight;
             }
         }
+
+        // Step 2: fill the remaining weight-1 independent txs greedily, each
+        // one going to whichever partition currently has the least load.
+        // `pending_txs` only ever holds txs actually bucketed under
+        // RAW_TRANSFER_WEIGHT above (any independent tx with a heavier real
+        // tx_weight was bucketed into `weighted_group` and placed in Step 1
+        // instead), so every pending tx genuinely carries the same unit
+        // weight here. That makes this closed-form greedy already
+        // makespan-optimal for this step (no two pending txs can ever be
+        // swapped to reduce the max load), and it runs in
+        // O(num_pending log num_partitions).
+        if !pending_txs.is_empty() {
+            let assignment = Self::greedy_fill_partitions(&pending_txs, &loads);
+            for (txid, partition) in pending_txs.into_iter().zip(assignment) {
+                partitioned_group[partition].insert(txid);
+            }
+        }
+
         partitioned_group
             .into_iter()
             .filter(|bs| !bs.is_empty())
@@ -233,6 +341,63 @@ impl TxDependency {
             .collect()
     }
 
+    /// Sums `tx_weight` over every member of `group`, used to weight a
+    /// dependency group by its true total cost rather than any single
+    /// member's cost.
+    fn sum_tx_weight(group: &DependentTxsVec, tx_weight: &[usize], num_finality_txs: usize) -> usize {
+        group.iter().map(|&tx| tx_weight[tx - num_finality_txs]).sum()
+    }
+
+    /// Returns the partition that held the majority of `group`'s txs in the
+    /// previous round, or `None` if none of them were previously assigned
+    /// (e.g. the first round, or a freshly-validated tx). Previous partitions
+    /// at or beyond `num_partitions` are ignored.
+    fn affinity_partition(
+        group: &DependentTxsVec,
+        prev_partition_of: &HashMap<TxId, PartitionId>,
+        num_partitions: usize,
+    ) -> Option<PartitionId> {
+        let mut votes = vec![0usize; num_partitions];
+        let mut any_vote = false;
+        for txid in group {
+            if let Some(&partition) = prev_partition_of.get(txid) {
+                if partition >= num_partitions {
+                    continue;
+                }
+                votes[partition] += 1;
+                any_vote = true;
+            }
+        }
+        if !any_vote {
+            return None;
+        }
+        votes.into_iter().enumerate().max_by_key(|&(_, votes)| votes).map(|(partition, _)| partition)
+    }
+
+    /// Assigns each of `pending_txs` (independent, weight-`RAW_TRANSFER_WEIGHT`
+    /// transactions) to one of `loads.len()` partitions whose current loads
+    /// are `loads`, minimizing the resulting makespan.
+    ///
+    /// Callers must only pass txs that are genuinely `RAW_TRANSFER_WEIGHT`
+    /// (see `fetch_best_partitions`'s Step 2, which buckets any heavier
+    /// independent tx elsewhere); every pending tx then carries the same
+    /// unit weight, so this reduces to scheduling identical unit jobs onto
+    /// machines with existing loads: the optimal (and only sensible)
+    /// strategy is to always place the next job on whichever partition
+    /// currently has the least load, which a `BinaryHeap` gives in
+    /// O(num_pending log num_partitions).
+    fn greedy_fill_partitions(pending_txs: &[TxId], loads: &[usize]) -> Vec<PartitionId> {
+        let mut heap: BinaryHeap<Reverse<(usize, PartitionId)>> =
+            loads.iter().enumerate().map(|(partition, &load)| Reverse((load, partition))).collect();
+        let mut assignment = Vec::with_capacity(pending_txs.len());
+        for _ in pending_txs {
+            let Reverse((load, partition)) = heap.pop().expect("loads is non-empty");
+            assignment.push(partition);
+            heap.push(Reverse((load + RAW_TRANSFER_WEIGHT, partition)));
+        }
+        assignment
+    }
+
     pub fn update_tx_dependency(
         &mut self,
         tx_dependency: Vec<DependentTxsVec>,
@@ -247,3 +412,104 @@ impl TxDependency {
         self.num_finality_txs = num_finality_txs;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sum_tx_weight_adds_every_member_not_just_the_root() {
+        // A group whose BFS root is a cheap raw transfer but that also
+        // contains a heavy contract call must weigh as the sum of both, not
+        // `group.len() * tx_weight[root]`.
+        let tx_weight = vec![100, 1, 1];
+        let mut group = DependentTxsVec::new();
+        group.push(0);
+        group.push(1);
+        group.push(2);
+
+        assert_eq!(TxDependency::sum_tx_weight(&group, &tx_weight, 0), 102);
+    }
+
+    #[test]
+    fn affinity_partition_picks_previous_majority() {
+        let mut group = DependentTxsVec::new();
+        group.push(10);
+        group.push(11);
+        group.push(12);
+        let mut prev_partition_of: HashMap<TxId, PartitionId> = HashMap::new();
+        prev_partition_of.insert(10, 1);
+        prev_partition_of.insert(11, 1);
+        prev_partition_of.insert(12, 0);
+
+        assert_eq!(TxDependency::affinity_partition(&group, &prev_partition_of, 2), Some(1));
+    }
+
+    #[test]
+    fn affinity_partition_is_none_without_any_prior_assignment() {
+        let mut group = DependentTxsVec::new();
+        group.push(10);
+        let prev_partition_of: HashMap<TxId, PartitionId> = HashMap::new();
+
+        assert_eq!(TxDependency::affinity_partition(&group, &prev_partition_of, 2), None);
+    }
+
+    #[test]
+    fn heavy_independent_tx_is_not_pooled_as_a_unit_weight_transfer() {
+        // tx0 is a heavy, fully independent contract call (no dependency
+        // edges at all); tx1 is a genuine cheap independent transfer; tx2/tx3
+        // form a dependent pair so `all_independent` is false and the
+        // weighted path (not the `all_independent_partitions` shortcut)
+        // actually runs. tx3 depends on tx2, so tx_dependency[3] names the
+        // lower tx it relies on, as `generate_tx_dependency` builds it.
+        let mut tx3_deps = DependentTxsVec::new();
+        tx3_deps.push(2);
+        let mut dep = TxDependency {
+            tx_dependency: vec![
+                DependentTxsVec::new(),
+                DependentTxsVec::new(),
+                DependentTxsVec::new(),
+                tx3_deps,
+            ],
+            num_finality_txs: 0,
+            tx_running_time: None,
+            tx_weight: Some(vec![100, 1, 1, 1]),
+            all_independent: false,
+            stability_factor: DEFAULT_STABILITY_FACTOR,
+        };
+
+        let partitions = dep.fetch_best_partitions(2, &[]);
+
+        // every tx must be placed exactly once
+        let mut all_txs: Vec<TxId> = partitions.iter().flatten().copied().collect();
+        all_txs.sort_unstable();
+        assert_eq!(all_txs, vec![0, 1, 2, 3]);
+
+        // tx0's real weight (100) must have kept it out of the unit-weight
+        // fill that handles tx1: it should end up alone in its partition,
+        // not folded in alongside tx1 as if it only cost RAW_TRANSFER_WEIGHT
+        let tx0_partition = partitions.iter().find(|p| p.contains(&0)).unwrap();
+        assert_eq!(tx0_partition, &vec![0]);
+    }
+
+    #[test]
+    fn greedy_fill_partitions_balances_against_existing_loads() {
+        let pending_txs: Vec<TxId> = (100..108).collect();
+        let loads = vec![0, 5];
+        let assignment = TxDependency::greedy_fill_partitions(&pending_txs, &loads);
+
+        let mut final_loads = loads.clone();
+        for &partition in &assignment {
+            final_loads[partition] += RAW_TRANSFER_WEIGHT;
+        }
+        // every pending tx must land somewhere, and the result must be as
+        // balanced as unit-weight jobs allow (loads differ by at most 1)
+        assert_eq!(
+            final_loads.iter().sum::<usize>(),
+            loads.iter().sum::<usize>() + pending_txs.len()
+        );
+        let max_load = *final_loads.iter().max().unwrap();
+        let min_load = *final_loads.iter().min().unwrap();
+        assert!(max_load - min_load <= 1, "loads should differ by at most 1: {:?}", final_loads);
+    }
+}